@@ -17,6 +17,9 @@ fn panic(info: &PanicInfo) -> ! {
     writeln!(out, "{}", info).ok();
 
     loop {
+        // Traps into the attached debugger so the host can detect the halt and print a
+        // backtrace, instead of spinning forever waiting for a manual Ctrl-C.
+        cortex_m::asm::bkpt();
         atomic::compiler_fence(Ordering::SeqCst);
     }
 }
@@ -0,0 +1,251 @@
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use addr2line::gimli;
+use anyhow::Result;
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianRcSlice, Register, RegisterRule,
+    RunTimeEndian, UninitializedUnwindContext, UnwindSection, UnwindTableRow,
+};
+use goblin::elf::Elf;
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+/// One resolved stack frame: a return address plus, when DWARF line info covers it, the
+/// function name and source location probe-run-style output wants.
+pub struct Frame {
+    pub pc: u32,
+    pub function: Option<String>,
+    pub location: Option<(PathBuf, u32)>,
+}
+
+/// Core registers needed to unwind Cortex-M call frames: R0-R12 (the CFI may reference any of
+/// them as a CFA base), SP, LR and PC.
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub r: [u32; 13],
+    pub sp: u32,
+    pub lr: u32,
+    pub pc: u32,
+}
+
+impl Registers {
+    /// Reads the register file off a halted core.
+    pub fn read(core: &mut Core) -> Result<Self> {
+        let mut r = [0u32; 13];
+        for (i, slot) in r.iter_mut().enumerate() {
+            *slot = core.read_core_reg(CoreRegisterAddress(i as u16))?;
+        }
+
+        Ok(Registers {
+            r,
+            sp: core.read_core_reg(CoreRegisterAddress(13))?,
+            lr: core.read_core_reg(CoreRegisterAddress(14))?,
+            pc: core.read_core_reg(CoreRegisterAddress(15))?,
+        })
+    }
+
+    fn register(&self, number: u16) -> u32 {
+        match number {
+            0..=12 => self.r[number as usize],
+            13 => self.sp,
+            14 => self.lr,
+            _ => self.pc,
+        }
+    }
+
+    fn set_register(&mut self, number: u16, value: u32) {
+        match number {
+            0..=12 => self.r[number as usize] = value,
+            13 => self.sp = value,
+            14 => self.lr = value,
+            _ => self.pc = value,
+        }
+    }
+}
+
+/// DWARF CFI, from whichever of `.debug_frame` (the common case for no_std/embedded builds,
+/// which have no unwind tables for exception propagation) or `.eh_frame` the ELF carries.
+/// The two encode CIEs/FDEs slightly differently (augmentation, pointer encoding), so they
+/// can't be parsed interchangeably with the same section type.
+enum Cfi {
+    Debug(DebugFrame<EndianRcSlice<RunTimeEndian>>),
+    Eh(EhFrame<EndianRcSlice<RunTimeEndian>>),
+}
+
+impl Cfi {
+    fn unwind_info_for_address(
+        &self,
+        bases: &BaseAddresses,
+        ctx: &mut UninitializedUnwindContext<EndianRcSlice<RunTimeEndian>>,
+        pc: u64,
+    ) -> gimli::Result<UnwindTableRow<EndianRcSlice<RunTimeEndian>>> {
+        match self {
+            Cfi::Debug(section) => section
+                .unwind_info_for_address(bases, ctx, pc, DebugFrame::cie_from_offset)
+                .cloned(),
+            Cfi::Eh(section) => section
+                .unwind_info_for_address(bases, ctx, pc, EhFrame::cie_from_offset)
+                .cloned(),
+        }
+    }
+}
+
+/// `LR` holds this pattern (the `EXC_RETURN` value) instead of a real return address when the
+/// processor entered an exception; in that case it auto-stacked R0-R3, R12, LR, PC and xPSR
+/// onto the exception frame at `SP`, and the real return address is the stacked PC.
+const EXC_RETURN_MARKER: u32 = 0xffff_fff0;
+
+/// Returns the stacked `(PC, LR)` of the exception frame at `SP` if `LR` is an `EXC_RETURN`
+/// value, `None` otherwise.
+fn exception_return_pc(registers: &Registers, core: &mut Core) -> Result<Option<(u32, u32)>> {
+    if registers.lr & EXC_RETURN_MARKER != EXC_RETURN_MARKER {
+        return Ok(None);
+    }
+
+    let mut stacked = [0u8; 8 * 4];
+    core.read_8(registers.sp, &mut stacked)?;
+    let stacked_lr = u32::from_le_bytes(stacked[20..24].try_into().unwrap());
+    let stacked_pc = u32::from_le_bytes(stacked[24..28].try_into().unwrap());
+
+    Ok(Some((stacked_pc, stacked_lr)))
+}
+
+/// Unwinds the call stack starting at `registers`, using `.debug_frame`/`.eh_frame` CFI to
+/// recover each caller's PC and canonical frame address, and symbolicating every PC against
+/// the ELF's DWARF line tables. Stops at `Reset` or `main`, once it can no longer find unwind
+/// info, or after a generous frame limit, in case the CFI is bogus.
+pub fn unwind(elf_buffer: &[u8], core: &mut Core, registers: Registers) -> Result<Vec<Frame>> {
+    let elf = Elf::parse(elf_buffer)?;
+    let dwarf = gimli::Dwarf::load(|section| -> Result<_, gimli::Error> {
+        let data = section_data(&elf, elf_buffer, section.name()).unwrap_or(&[]);
+        Ok(EndianRcSlice::new(Rc::from(data.to_vec().into_boxed_slice()), RunTimeEndian::Little))
+    })?;
+    let ctx = addr2line::Context::from_dwarf(dwarf).ok();
+
+    let cfi = match section_data(&elf, elf_buffer, ".debug_frame") {
+        Some(data) => Cfi::Debug(DebugFrame::new(data, RunTimeEndian::Little)),
+        None => {
+            let data = section_data(&elf, elf_buffer, ".eh_frame").unwrap_or(&[]);
+            Cfi::Eh(EhFrame::new(data, RunTimeEndian::Little))
+        }
+    };
+    let bases = BaseAddresses::default();
+    let mut unwind_ctx = UninitializedUnwindContext::new();
+
+    let mut frames = Vec::new();
+    let mut regs = registers;
+    if let Some((stacked_pc, stacked_lr)) = exception_return_pc(&registers, core)? {
+        // The halted PC is the innermost frame; its caller resumes at the stacked PC, with SP
+        // just past the eight auto-stacked words.
+        frames.push(symbolicate(ctx.as_ref(), regs.pc));
+        regs.pc = stacked_pc;
+        regs.lr = stacked_lr;
+        regs.sp = registers.sp + 8 * 4;
+    }
+
+    loop {
+        frames.push(symbolicate(ctx.as_ref(), regs.pc));
+
+        if is_entry_point(ctx.as_ref(), regs.pc) || frames.len() > 100 {
+            break;
+        }
+
+        let unwind_info = match cfi.unwind_info_for_address(&bases, &mut unwind_ctx, regs.pc as u64) {
+            Ok(info) => info,
+            Err(_) => break,
+        };
+
+        let cfa = match unwind_info.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                (regs.register(register.0) as i64 + offset) as u32
+            }
+            CfaRule::Expression(_) => break,
+        };
+
+        let return_address = match unwind_info.register(Register(14)) {
+            RegisterRule::Offset(offset) => {
+                let addr = (cfa as i64 + offset) as u32;
+                let mut word = [0u8; 4];
+                core.read_8(addr, &mut word)?;
+                u32::from_le_bytes(word)
+            }
+            RegisterRule::SameValue => regs.lr,
+            _ => break,
+        };
+
+        if return_address == 0 || return_address == regs.pc {
+            break;
+        }
+
+        // Apply every other register's CFI rule (restoring callee-saved registers the CFA may
+        // be computed from in the next frame) before moving SP to this frame's CFA, so the
+        // following iteration unwinds relative to the right frame instead of the entry state.
+        let previous = regs;
+        for number in 0..=12u16 {
+            let value = match unwind_info.register(Register(number)) {
+                RegisterRule::Undefined | RegisterRule::SameValue => previous.register(number),
+                RegisterRule::Offset(offset) => {
+                    let addr = (cfa as i64 + offset) as u32;
+                    let mut word = [0u8; 4];
+                    core.read_8(addr, &mut word)?;
+                    u32::from_le_bytes(word)
+                }
+                RegisterRule::ValOffset(offset) => (cfa as i64 + offset) as u32,
+                RegisterRule::Register(other) => previous.register(other.0),
+                _ => previous.register(number),
+            };
+            regs.set_register(number, value);
+        }
+        regs.sp = cfa;
+        regs.lr = return_address;
+        regs.pc = return_address;
+    }
+
+    Ok(frames)
+}
+
+fn symbolicate(ctx: Option<&addr2line::Context<EndianRcSlice<RunTimeEndian>>>, pc: u32) -> Frame {
+    let ctx = match ctx {
+        Some(ctx) => ctx,
+        None => return Frame { pc, function: None, location: None },
+    };
+
+    let function = ctx
+        .find_frames(pc as u64)
+        .ok()
+        .and_then(|mut frames| frames.next().ok().flatten())
+        .and_then(|frame| frame.function)
+        .and_then(|f| f.demangle().ok().map(|s| s.into_owned()));
+
+    let location = ctx.find_location(pc as u64).ok().flatten().and_then(|loc| {
+        let file = loc.file?;
+        let line = loc.line?;
+        Some((PathBuf::from(file), line))
+    });
+
+    Frame { pc, function, location }
+}
+
+fn is_entry_point(ctx: Option<&addr2line::Context<EndianRcSlice<RunTimeEndian>>>, pc: u32) -> bool {
+    let ctx = match ctx {
+        Some(ctx) => ctx,
+        None => return false,
+    };
+
+    ctx.find_frames(pc as u64)
+        .ok()
+        .and_then(|mut frames| frames.next().ok().flatten())
+        .and_then(|frame| frame.function)
+        .and_then(|f| f.raw_name().ok().map(|n| n.into_owned()))
+        .map(|name| name == "main" || name == "Reset")
+        .unwrap_or(false)
+}
+
+fn section_data<'a>(elf: &Elf, buffer: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let sh = elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(name))?;
+    buffer.get(sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize)
+}
@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+use goblin::elf::{program_header::PT_LOAD, Elf};
+use probe_rs::{MemoryInterface, Session};
+use probe_rs_rtt::ScanRegion;
+
+/// Byte pattern painted over unused RAM before a run so the high-water mark can be recovered
+/// afterwards.
+const CANARY_VALUE: u8 = 0xAA;
+
+/// Upper bound on how much RAM gets painted in one run, so `reset` stays fast on chips with a
+/// lot of RAM. If the real unused-stack region is bigger, only this sub-window is painted, and
+/// the measured usage is scaled up from it when the sub-window is entirely overwritten.
+const MAX_CANARY_SIZE: u32 = 64 * 1024;
+
+/// Stack-overflow canary: paints a known pattern over the RAM between the end of statically
+/// allocated data and the initial stack pointer, then inspects how much of it survived after
+/// the run to report peak stack usage.
+pub struct Canary {
+    range: Range<u32>,
+    /// Real size of the unused-stack region (`initial_sp - ram_end`), which `range` may be a
+    /// truncated sub-window of.
+    full_size: u32,
+    /// `true` if `range` was truncated to [`MAX_CANARY_SIZE`], so [`StackUsage::used`] is
+    /// scaled up from a sub-window rather than measured exactly once that sub-window is fully
+    /// overwritten, and overflow can't be detected (the painted window doesn't reach `ram_end`,
+    /// the real stack limit).
+    truncated: bool,
+}
+
+impl Canary {
+    /// Determines the unused-stack region from the ELF and paints it with [`CANARY_VALUE`].
+    ///
+    /// `ram_end` is the highest address used by any statically-allocated RAM section (the end
+    /// of `.data`/`.bss`), i.e. the lowest address the stack may grow down into.
+    pub fn paint(session: &mut Session, elf: &Elf, buffer: &[u8], ram_end: u32) -> Result<Option<Self>> {
+        let initial_sp = initial_stack_pointer(elf, buffer)?;
+        if initial_sp <= ram_end {
+            log::warn!("initial stack pointer is below the end of static RAM; skipping stack canary");
+            return Ok(None);
+        }
+
+        let full_size = initial_sp - ram_end;
+        let size = full_size.min(MAX_CANARY_SIZE);
+        let start = initial_sp - size;
+        let range = start..initial_sp;
+
+        let pattern = vec![CANARY_VALUE; range.len()];
+        session.core(0)?.write_8(range.start, &pattern)?;
+
+        Ok(Some(Canary {
+            range,
+            full_size,
+            truncated: size < full_size,
+        }))
+    }
+
+    /// Reads the painted region back and reports the high-water mark, scaled up if the canary
+    /// window was truncated and entirely overwritten.
+    pub fn measure(&self, session: &mut Session) -> Result<StackUsage> {
+        let mut core = session.core(0)?;
+        let mut observed = vec![0u8; self.range.len() as usize];
+        core.read_8(self.range.start, &mut observed)?;
+
+        let untouched = observed
+            .iter()
+            .position(|&byte| byte != CANARY_VALUE)
+            .unwrap_or(observed.len());
+        let touched = observed.len() - untouched;
+
+        Ok(usage_from_touched(touched, self.range.len() as u32, self.full_size, self.truncated))
+    }
+}
+
+/// Turns a raw touched-byte count into a [`StackUsage`], applying the truncated-window
+/// extrapolation rule `measure` depends on: `touched` is only a floor when the window itself
+/// was fully consumed, since the window is painted at the top of the stack where it actually
+/// grows down from. If usage stopped partway through a truncated window, that's the exact
+/// high-water mark already -- scaling it up would inflate a correct measurement.
+fn usage_from_touched(touched: usize, window_len: u32, full_size: u32, truncated: bool) -> StackUsage {
+    let window_len = window_len as usize;
+    let fully_consumed = touched == window_len;
+
+    let used = if truncated && fully_consumed {
+        let scale = full_size as f64 / window_len as f64;
+        (touched as f64 * scale).round() as u32
+    } else {
+        touched as u32
+    };
+
+    StackUsage {
+        used,
+        total: full_size,
+        // The painted window only reaches `ram_end` (the real stack limit) when it wasn't
+        // truncated; filling a truncated sub-window says nothing about the real limit.
+        overflowed: fully_consumed && !truncated,
+        estimated: truncated && fully_consumed,
+    }
+}
+
+/// Peak stack usage observed by a [`Canary`].
+pub struct StackUsage {
+    /// Bytes of the canary window that were overwritten by the program, scaled up to the whole
+    /// unused-stack region when `estimated` is set.
+    pub used: u32,
+    /// Size of the whole unused-stack region (`initial_sp - ram_end`) in bytes, which may be
+    /// larger than the painted window.
+    pub total: u32,
+    /// `true` if the canary was overwritten all the way down to the stack limit: a probable
+    /// stack overflow.
+    pub overflowed: bool,
+    /// `true` if `used` is scaled up from a truncated window rather than measured
+    /// directly over the whole unused-stack region.
+    pub estimated: bool,
+}
+
+impl std::fmt::Display for StackUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let approx = if self.estimated { "~" } else { "" };
+        write!(f, "stack usage: {}{}/{} bytes", approx, self.used, self.total)?;
+        if self.overflowed {
+            write!(f, " -- probable stack overflow")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the initial stack pointer straight out of the ELF file contents: it's the first word
+/// of the vector table, which lives at the start of the lowest `PT_LOAD` segment (flash).
+fn initial_stack_pointer(elf: &Elf, buffer: &[u8]) -> Result<u32> {
+    let vector_table = elf
+        .program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .min_by_key(|ph| ph.p_vaddr)
+        .context("ELF has no loadable segments; cannot locate the vector table")?;
+
+    let offset = vector_table.p_offset as usize;
+    let word = buffer
+        .get(offset..offset + 4)
+        .context("vector table is truncated in the ELF file")?;
+
+    Ok(u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+/// Highest address used by any statically-allocated RAM section, i.e. the end of `.data`/`.bss`.
+pub fn ram_static_end(ram_ranges: &[ScanRegion]) -> Option<u32> {
+    ram_ranges
+        .iter()
+        .filter_map(|region| match region {
+            ScanRegion::Range(range) => Some(range.end),
+            _ => None,
+        })
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn untruncated_window_reports_usage_exactly() {
+        let usage = usage_from_touched(100, 1000, 1000, false);
+        assert_eq!(usage.used, 100);
+        assert_eq!(usage.total, 1000);
+        assert!(!usage.overflowed);
+        assert!(!usage.estimated);
+    }
+
+    #[test]
+    fn untruncated_window_fully_consumed_is_an_overflow() {
+        let usage = usage_from_touched(1000, 1000, 1000, false);
+        assert_eq!(usage.used, 1000);
+        assert!(usage.overflowed);
+        assert!(!usage.estimated);
+    }
+
+    #[test]
+    fn truncated_window_partially_consumed_is_exact_not_scaled() {
+        // High-water mark landed inside the painted window: that's the real usage already, even
+        // though the window is a truncated sub-range of the full unused-stack region.
+        let usage = usage_from_touched(100, 1000, 64_000, true);
+        assert_eq!(usage.used, 100);
+        assert_eq!(usage.total, 64_000);
+        assert!(!usage.overflowed);
+        assert!(!usage.estimated);
+    }
+
+    #[test]
+    fn truncated_window_fully_consumed_is_scaled_up() {
+        // The painted window was entirely overwritten, so the true usage is unknown; extrapolate
+        // from the window size to the full unused-stack region, an upper-bound estimate.
+        let usage = usage_from_touched(1000, 1000, 64_000, true);
+        assert_eq!(usage.used, 64_000);
+        assert_eq!(usage.total, 64_000);
+        // A truncated window filling up doesn't tell us anything about `ram_end`.
+        assert!(!usage.overflowed);
+        assert!(usage.estimated);
+    }
+}
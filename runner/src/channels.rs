@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use probe_rs_rtt::{Rtt, UpChannel};
+
+/// Where a channel's bytes should go. Stdout output is prefixed with the channel's declared
+/// name so interleaved channels stay distinguishable; a file needs no prefix since the file
+/// itself is the channel's stream.
+enum Sink {
+    Stdout { prefix: String },
+    File(File),
+}
+
+impl Sink {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        match self {
+            Sink::Stdout { prefix } => {
+                let mut stdout = std::io::stdout();
+                for line in bytes.split_inclusive(|&b| b == b'\n') {
+                    write!(stdout, "[{}] ", prefix)?;
+                    stdout.write_all(line)?;
+                }
+                stdout.flush().ok();
+            }
+            Sink::File(file) => file.write_all(bytes)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// One up-channel being polled in the round-robin loop, paired with where its bytes go.
+pub struct Demuxed {
+    channel: UpChannel,
+    sink: Sink,
+}
+
+impl Demuxed {
+    /// Polls this channel once and forwards anything read to its sink.
+    pub fn poll(&mut self, buf: &mut [u8]) -> Result<()> {
+        let count = self.channel.read(buf)?;
+        if count > 0 {
+            self.sink.write(&buf[..count])?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `--channel-file name=path` arguments into a name-to-path map.
+pub fn parse_channel_files(args: &[String]) -> Result<HashMap<String, PathBuf>> {
+    args.iter()
+        .map(|arg| {
+            let (name, path) = arg
+                .split_once('=')
+                .with_context(|| format!("expected `name=path`, got `{}`", arg))?;
+            Ok((name.to_owned(), PathBuf::from(path)))
+        })
+        .collect()
+}
+
+/// Takes every up-channel still left on `rtt` (i.e. not already claimed by e.g. the defmt
+/// decoder) and pairs each with its output sink: a file if `file_overrides` names it by its
+/// declared channel name, stdout (prefixed with that name) otherwise.
+pub fn take_remaining(rtt: &mut Rtt, file_overrides: &HashMap<String, PathBuf>) -> Result<Vec<Demuxed>> {
+    let mut channels = Vec::new();
+
+    // `take` addresses channels by their RTT channel number, but `up_channels().len()` only
+    // counts the channels still present -- once defmt/RPC have claimed lower-numbered channels,
+    // `0..len()` no longer spans the remaining channels' actual numbers. Snapshot those numbers
+    // first so every remaining channel gets taken exactly once.
+    let numbers: Vec<usize> = rtt.up_channels().iter().map(|channel| channel.number()).collect();
+
+    for number in numbers {
+        let channel = match rtt.up_channels().take(number) {
+            Some(channel) => channel,
+            None => continue,
+        };
+
+        let name = channel
+            .name()
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("channel{}", number));
+
+        let sink = match file_overrides.get(&name) {
+            Some(path) => Sink::File(File::create(path)?),
+            None => Sink::Stdout { prefix: name },
+        };
+
+        channels.push(Demuxed { channel, sink });
+    }
+
+    Ok(channels)
+}
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use defmt_decoder::{DecodeError, Locations, StreamDecoder, Table};
+
+/// Decodes defmt-encoded frames read from an RTT channel into human-readable log lines.
+///
+/// Wraps the `defmt_decoder` streaming decoder so the caller can feed it raw bytes as they
+/// arrive from `read()` without worrying about frame boundaries: the decoder buffers partial
+/// frames internally and yields complete ones as they become available.
+pub struct DefmtState {
+    locations: Option<Locations>,
+    decoder: Box<dyn StreamDecoder>,
+}
+
+impl DefmtState {
+    /// Parses the `.defmt` section of the given ELF file and prepares a streaming decoder.
+    ///
+    /// Returns `Ok(None)` if the ELF carries no `.defmt` section, meaning the firmware wasn't
+    /// built with defmt support and the caller should fall back to plain passthrough.
+    pub fn new(elf_path: &Path) -> Result<Option<Self>> {
+        let elf = std::fs::read(elf_path)
+            .with_context(|| format!("failed to read {}", elf_path.display()))?;
+
+        let table = match Table::parse(&elf)? {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+
+        let locations = table.get_locations(&elf)?;
+        let locations = if locations.is_empty() || table.indices().all(|idx| locations.contains_key(&(idx as u64))) {
+            Some(locations)
+        } else {
+            log::warn!("(BUG) location info is incomplete; it will be omitted from the output");
+            None
+        };
+
+        let decoder = table.new_stream_decoder();
+
+        Ok(Some(DefmtState { locations, decoder }))
+    }
+
+    /// Feeds newly-read RTT bytes into the decoder and prints every complete frame found.
+    pub fn process(&mut self, bytes: &[u8]) -> Result<()> {
+        self.decoder.received(bytes);
+
+        loop {
+            match self.decoder.decode() {
+                Ok(frame) => self.print_frame(&frame),
+                Err(DecodeError::UnexpectedEof) => break,
+                Err(DecodeError::Malformed) => {
+                    eprintln!("(defmt) malformed frame, resynchronizing");
+                    continue;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn print_frame(&self, frame: &defmt_decoder::Frame) {
+        let level = frame
+            .level()
+            .map(|level| level.as_str().to_uppercase())
+            .unwrap_or_else(|| "LOG".to_owned());
+
+        let location = self
+            .locations
+            .as_ref()
+            .and_then(|locations| locations.get(&frame.index()))
+            .map(|loc| format!(" @ {}:{}", loc.file.display(), loc.line))
+            .unwrap_or_default();
+
+        println!(
+            "{} [{}]{} {}",
+            frame.display_timestamp().unwrap_or_default(),
+            level,
+            location,
+            frame.display_message()
+        );
+    }
+}
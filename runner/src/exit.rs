@@ -0,0 +1,87 @@
+use std::convert::TryInto;
+
+use anyhow::Result;
+use probe_rs::{Core, CoreRegisterAddress, CoreStatus, MemoryInterface};
+
+/// Encoding of the Thumb `bkpt #imm` instruction: the top byte is fixed, the bottom byte is
+/// the immediate.
+const BKPT_OPCODE_MASK: u16 = 0xff00;
+const BKPT_OPCODE: u16 = 0xbe00;
+
+/// ARM semihosting uses `bkpt #0xAB` to trap into the debugger for its syscalls.
+const SEMIHOSTING_BKPT_IMM: u8 = 0xab;
+/// Semihosting `SYS_EXIT` operation number (R0 on entry): a bare reason code in R1, no subcode.
+const SYS_EXIT: u32 = 0x18;
+/// Semihosting `SYS_EXIT_EXTENDED` operation number: R1 points to a `{reason, subcode}` block in
+/// target memory instead of carrying a bare reason code. `cortex-m-semihosting`'s `debug::exit`
+/// always uses this form, with the real process exit code in `subcode`.
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+/// `ADP_Stopped_ApplicationExit` reason code, passed by a successful exit call.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Why the target halted on its own, as opposed to being stopped by Ctrl-C.
+pub enum ExitReason {
+    /// The panic handler's `bkpt` fired.
+    Panic,
+    /// The firmware called the semihosting exit operation with the given process exit code.
+    Exit(i32),
+}
+
+/// If the core is halted on a breakpoint, figures out whether that's the panic handler's
+/// `bkpt` or a semihosting program-exit request, so the runner can propagate a meaningful
+/// process exit code instead of looping forever.
+pub fn detect(core: &mut Core) -> Result<Option<ExitReason>> {
+    if !matches!(core.status()?, CoreStatus::Halted(_)) {
+        return Ok(None);
+    }
+
+    let pc = core.read_core_reg(CoreRegisterAddress(15))?;
+    let mut insn_bytes = [0u8; 2];
+    core.read_8(pc, &mut insn_bytes)?;
+    let insn = u16::from_le_bytes(insn_bytes);
+
+    if insn & BKPT_OPCODE_MASK != BKPT_OPCODE {
+        // Halted by something other than a software breakpoint (e.g. a manual halt); nothing
+        // to report here.
+        return Ok(None);
+    }
+
+    let imm = (insn & 0x00ff) as u8;
+    if imm != SEMIHOSTING_BKPT_IMM {
+        return Ok(Some(ExitReason::Panic));
+    }
+
+    let r0 = core.read_core_reg(CoreRegisterAddress(0))?;
+    let r1 = core.read_core_reg(CoreRegisterAddress(1))?;
+
+    let code = match r0 {
+        SYS_EXIT_EXTENDED => {
+            // R1 points to a `{reason: u32, subcode: u32}` block; the subcode is the real exit
+            // code the firmware passed to `debug::exit`.
+            let mut block = [0u8; 8];
+            core.read_8(r1, &mut block)?;
+            i32::from_le_bytes(block[4..8].try_into().unwrap())
+        }
+        SYS_EXIT => {
+            // Legacy form: R1 is a bare reason code with no subcode, so the best we can report
+            // is success/failure.
+            if r1 == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 }
+        }
+        // Some other semihosting operation (SYS_WRITE0, SYS_OPEN, ...); not a program exit, and
+        // definitely not our panic handler's bkpt, so there's nothing to report yet.
+        _ => return Ok(None),
+    };
+
+    Ok(Some(ExitReason::Exit(code)))
+}
+
+impl ExitReason {
+    /// The process exit code this should be reported as. Mirrors the common convention of
+    /// mapping a panic to a SIGABRT-like non-zero code.
+    pub fn process_exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Panic => 134,
+            ExitReason::Exit(code) => *code,
+        }
+    }
+}
@@ -8,7 +8,6 @@ use probe_rs::{
 };
 use probe_rs_rtt::{Rtt, ScanRegion};
 use std::io::prelude::*;
-use std::io::stdout;
 use std::path::{Path, PathBuf};
 use std::{
     sync::{Arc, Mutex},
@@ -16,6 +15,18 @@ use std::{
 };
 use structopt::StructOpt;
 
+mod backtrace;
+mod canary;
+mod channels;
+mod defmt;
+mod exit;
+mod rpc;
+
+use backtrace::Registers;
+use canary::Canary;
+use defmt::DefmtState;
+use rpc::RpcServer;
+
 #[derive(StructOpt, Clone)]
 struct Opts {
     #[structopt(short, long)]
@@ -26,6 +37,31 @@ struct Opts {
     verbose: bool,
     #[structopt(long, short)]
     no_halt_on_exit: bool,
+    /// Decode the RTT stream as defmt frames instead of treating it as plain text.
+    #[structopt(long)]
+    defmt: bool,
+    /// Index of the up-channel the defmt decoder attaches to; the rest are demuxed as
+    /// plain text.
+    #[structopt(long, default_value = "0")]
+    defmt_channel: usize,
+    /// Write an up-channel's plain-text output to a file instead of stdout, as `name=path`.
+    /// May be given multiple times.
+    #[structopt(long = "channel-file")]
+    channel_file: Vec<String>,
+    /// Paint unused RAM with a known pattern before the run and report peak stack usage
+    /// (and probable stack overflows) once the chip halts.
+    #[structopt(long)]
+    measure_stack: bool,
+    /// Don't print a symbolicated backtrace when the chip halts.
+    #[structopt(long)]
+    no_backtrace: bool,
+    /// Forward stdin to down-channel 0 so firmware can receive interactive input.
+    #[structopt(long)]
+    stdin: bool,
+    /// Run the RPC subsystem on up/down channel 1, letting firmware call back into
+    /// host-side services.
+    #[structopt(long)]
+    rpc: bool,
     target: PathBuf,
 }
 
@@ -81,27 +117,25 @@ fn try_main() -> Result<()> {
 
     let session = probe.attach(target_selector)?;
     let session = Arc::new(Mutex::new(session));
+    let canary: Arc<Mutex<Option<Canary>>> = Arc::new(Mutex::new(None));
 
     {
         let opts = opts.clone();
         let session = session.clone();
+        let canary = canary.clone();
         ctrlc::set_handler(move || {
             if !opts.no_halt_on_exit {
                 println!("halting chip");
-                session
-                    .lock()
-                    .unwrap()
-                    .core(0)
-                    .unwrap()
-                    .halt(Duration::from_secs(5))
-                    .unwrap();
+                let mut session = session.lock().unwrap();
+                session.core(0).unwrap().halt(Duration::from_secs(5)).unwrap();
+                report_halt(&mut session, &opts, &canary);
             }
             std::process::exit(0);
         })
         .expect("Error setting Ctrl-C handler");
     }
 
-    match run(session, &opts) {
+    match run(session, canary, &opts) {
         Err(e) => {
             return Err(e);
         }
@@ -109,6 +143,49 @@ fn try_main() -> Result<()> {
     }
 }
 
+/// Runs the post-halt diagnostics shared by the Ctrl-C handler and program-exit detection: a
+/// symbolicated backtrace and, if a stack canary was painted, its peak-usage report.
+fn report_halt(session: &mut Session, opts: &Opts, canary: &Mutex<Option<Canary>>) {
+    if !opts.no_backtrace {
+        print_backtrace(session, &opts.target);
+    }
+
+    if let Some(canary) = canary.lock().unwrap().as_ref() {
+        match canary.measure(session) {
+            Ok(usage) => println!("{}", usage),
+            Err(e) => eprintln!("Error measuring stack canary: {:?}", e),
+        }
+    }
+}
+
+/// Reads the halted core's registers, unwinds the call stack and prints it. Errors are
+/// reported but don't stop the Ctrl-C handler from running the rest of its cleanup.
+fn print_backtrace(session: &mut Session, elf_path: &Path) {
+    let result = (|| -> Result<()> {
+        let elf_buffer = std::fs::read(elf_path)?;
+        let mut core = session.core(0)?;
+        let registers = Registers::read(&mut core)?;
+        let frames = backtrace::unwind(&elf_buffer, &mut core, registers)?;
+
+        println!("stack backtrace:");
+        for (i, frame) in frames.iter().enumerate() {
+            let function = frame.function.as_deref().unwrap_or("<unknown>");
+            match &frame.location {
+                Some((file, line)) => {
+                    println!("{:>4}: {} ({}:{})", i, function, file.display(), line)
+                }
+                None => println!("{:>4}: {} (0x{:08x})", i, function, frame.pc),
+            }
+        }
+
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        eprintln!("Error computing backtrace: {:?}", e);
+    }
+}
+
 fn get_ram_memory_ranges(session: &Session, file: &Path) -> Result<Vec<ScanRegion>> {
     let buffer = std::fs::read(&file)?;
     let binary = goblin::elf::Elf::parse(&buffer.as_slice())?;
@@ -139,7 +216,7 @@ fn get_ram_memory_ranges(session: &Session, file: &Path) -> Result<Vec<ScanRegio
         .collect())
 }
 
-fn run(session: Arc<Mutex<Session>>, opts: &Opts) -> Result<()> {
+fn run(session: Arc<Mutex<Session>>, canary: Arc<Mutex<Option<Canary>>>, opts: &Opts) -> Result<()> {
     if opts.verbose {
         println!("{} Flashing", style("[1/3]").bold().dim());
     }
@@ -158,11 +235,22 @@ fn run(session: Arc<Mutex<Session>>, opts: &Opts) -> Result<()> {
         )
         .unwrap();
 
+        ram_ranges = get_ram_memory_ranges(&guard, &opts.target)?;
+
+        if opts.measure_stack {
+            let buffer = std::fs::read(&opts.target)?;
+            let elf = goblin::elf::Elf::parse(&buffer)?;
+            if let Some(ram_end) = canary::ram_static_end(&ram_ranges) {
+                *canary.lock().unwrap() = Canary::paint(&mut guard, &elf, &buffer, ram_end)?;
+            } else {
+                log::warn!("no RAM sections found in the ELF; skipping stack canary");
+            }
+        }
+
         if opts.verbose {
             println!("{} Resetting", style("[2/3]").bold().dim());
         }
         guard.core(0)?.reset()?;
-        ram_ranges = get_ram_memory_ranges(&guard, &opts.target)?;
     }
 
     let spinner_style = ProgressStyle::default_spinner()
@@ -196,28 +284,148 @@ fn run(session: Arc<Mutex<Session>>, opts: &Opts) -> Result<()> {
 
     rtt_spinner.finish_with_message("Attached to RTT");
 
+    let mut defmt_state = if opts.defmt {
+        DefmtState::new(&opts.target)?
+    } else {
+        None
+    };
+    if opts.defmt && defmt_state.is_none() {
+        log::warn!("--defmt was passed but the ELF has no `.defmt` section; falling back to plain text");
+    }
+
+    // Only claim the channel if the defmt decoder actually came up; if the ELF has no `.defmt`
+    // section, leave it on `rtt` so `take_remaining` demuxes it as plain text below instead of
+    // the output being silently dropped.
+    let defmt_channel = if opts.defmt && defmt_state.is_some() {
+        let channel = rtt.up_channels().take(opts.defmt_channel);
+        if channel.is_none() {
+            log::warn!("--defmt was passed but up-channel {} is not present", opts.defmt_channel);
+        }
+        channel
+    } else {
+        None
+    };
+
+    if opts.stdin {
+        match rtt.down_channels().take(0) {
+            Some(down_channel) => {
+                std::thread::spawn(move || forward_stdin(down_channel));
+            }
+            None => log::warn!("--stdin was passed but down-channel 0 is not present"),
+        }
+    }
+
+    let rpc = if opts.rpc {
+        match (rtt.up_channels().take(1), rtt.down_channels().take(1)) {
+            (Some(up), Some(down)) => Some((up, down, rpc_server())),
+            _ => {
+                log::warn!("--rpc was passed but up/down channel 1 is not present; RPC disabled");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut rpc_buf = [0u8; 1024];
+
+    // Every up-channel not already claimed above (defmt, RPC) is demuxed as plain text, one
+    // name-prefixed stream per channel.
+    let file_overrides = channels::parse_channel_files(&opts.channel_file)?;
+    let mut plain_channels = channels::take_remaining(&mut rtt, &file_overrides)?;
+
     // TODO: reset halt chip on exit
-    let up_channel = rtt.up_channels().take(0);
-    let mut up_buf = [0u8; 1024];
+    let mut defmt_buf = [0u8; 1024];
+    let mut demux_buf = [0u8; 1024];
+    // `exit::detect` takes the session lock and round-trips over SWD; checking it every loop
+    // iteration serializes that round-trip against every RTT byte-poll and throttles throughput.
+    // Only pay for it once per interval instead.
+    const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+    let mut last_exit_check = std::time::Instant::now();
     loop {
-        if let Some(up_channel) = up_channel.as_ref() {
-            let count = match up_channel.read(up_buf.as_mut()) {
+        if let Some((up, down, server)) = rpc.as_ref() {
+            if let Err(e) = rpc::pump(up, down, server, &mut rpc_buf) {
+                eprintln!("Error handling RPC request: {:?}", e);
+            }
+        }
+
+        if let (Some(channel), Some(state)) = (defmt_channel.as_ref(), defmt_state.as_mut()) {
+            let count = match channel.read(defmt_buf.as_mut()) {
                 Ok(count) => count,
                 Err(err) => {
                     eprintln!("\nError reading from RTT: {}", err);
                     return Err(err.into());
                 }
             };
+            state.process(&defmt_buf[..count])?;
+        }
+
+        for channel in plain_channels.iter_mut() {
+            channel.poll(&mut demux_buf)?;
+        }
+
+        if last_exit_check.elapsed() < EXIT_CHECK_INTERVAL {
+            continue;
+        }
+        last_exit_check = std::time::Instant::now();
 
-            match stdout().write_all(&up_buf[..count]) {
-                Ok(_) => {
-                    stdout().flush().ok();
+        let mut guard = session.lock().unwrap();
+        let reason = exit::detect(&mut guard.core(0)?)?;
+        if let Some(reason) = reason {
+            // Flush whatever RTT output arrived between the last poll above and the halt.
+            if let (Some(channel), Some(state)) = (defmt_channel.as_ref(), defmt_state.as_mut()) {
+                loop {
+                    let count = channel.read(defmt_buf.as_mut())?;
+                    if count == 0 {
+                        break;
+                    }
+                    state.process(&defmt_buf[..count])?;
                 }
-                Err(err) => {
-                    eprintln!("Error writing to stdout: {}", err);
-                    return Err(err.into());
+            }
+            for channel in plain_channels.iter_mut() {
+                channel.poll(&mut demux_buf)?;
+            }
+
+            if !opts.no_halt_on_exit {
+                report_halt(&mut guard, opts, &canary);
+            }
+
+            std::process::exit(reason.process_exit_code());
+        }
+    }
+}
+
+/// Reads process stdin and forwards every byte into `down_channel`, so firmware can receive
+/// interactive input over RTT instead of only emitting one-way logs. Runs until stdin closes
+/// or a write fails.
+fn forward_stdin(down_channel: probe_rs_rtt::DownChannel) {
+    let stdin = std::io::stdin();
+    let mut buf = [0u8; 1024];
+    loop {
+        match stdin.lock().read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => {
+                if let Err(err) = down_channel.write(&buf[..count]) {
+                    eprintln!("Error writing to RTT down-channel: {}", err);
+                    break;
                 }
             }
+            Err(err) => {
+                eprintln!("Error reading stdin: {}", err);
+                break;
+            }
         }
     }
 }
+
+/// Host-side handlers for the optional RPC subsystem. Registered here rather than exposed for
+/// external configuration, same as the rest of this runner's behavior today.
+fn rpc_server() -> RpcServer {
+    let mut server = RpcServer::new();
+    server.register(0, |_args| {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        Ok(rpc::Value::U32(secs as u32))
+    });
+    server
+}
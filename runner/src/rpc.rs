@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+use probe_rs_rtt::{DownChannel, UpChannel};
+
+/// A single RPC argument or return value, tagged with its type so the wire format is
+/// self-describing on both ends. Modeled after ARTIQ's `rpc_send`/`rpc_recv`: a request is a
+/// method tag followed by a list of these, a reply is exactly one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    U32(u32),
+    I32(i32),
+    Bytes(Vec<u8>),
+    Str(String),
+}
+
+impl Value {
+    const TAG_U32: u8 = 0;
+    const TAG_I32: u8 = 1;
+    const TAG_BYTES: u8 = 2;
+    const TAG_STR: u8 = 3;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::U32(v) => {
+                out.push(Self::TAG_U32);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::I32(v) => {
+                out.push(Self::TAG_I32);
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Bytes(bytes) => {
+                out.push(Self::TAG_BYTES);
+                out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Value::Str(s) => {
+                out.push(Self::TAG_STR);
+                out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+
+    fn read(cursor: &mut &[u8]) -> Result<Self> {
+        let tag = take_u8(cursor)?;
+        Ok(match tag {
+            Self::TAG_U32 => Value::U32(u32::from_le_bytes(take_n(cursor)?)),
+            Self::TAG_I32 => Value::I32(i32::from_le_bytes(take_n(cursor)?)),
+            Self::TAG_BYTES => Value::Bytes(take_len_prefixed(cursor)?.to_vec()),
+            Self::TAG_STR => {
+                let bytes = take_len_prefixed(cursor)?;
+                Value::Str(String::from_utf8(bytes.to_vec())?)
+            }
+            other => bail!("unknown RPC value tag {}", other),
+        })
+    }
+}
+
+fn take_u8(cursor: &mut &[u8]) -> Result<u8> {
+    let (byte, rest) = cursor.split_first().context_missing()?;
+    *cursor = rest;
+    Ok(*byte)
+}
+
+fn take_n<const N: usize>(cursor: &mut &[u8]) -> Result<[u8; N]> {
+    if cursor.len() < N {
+        bail!("RPC frame truncated");
+    }
+    let (bytes, rest) = cursor.split_at(N);
+    *cursor = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn take_len_prefixed<'a>(cursor: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = u32::from_le_bytes(take_n(cursor)?) as usize;
+    if cursor.len() < len {
+        bail!("RPC frame truncated");
+    }
+    let (bytes, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(bytes)
+}
+
+trait ContextMissing<T> {
+    fn context_missing(self) -> Result<T>;
+}
+
+impl<T> ContextMissing<T> for Option<T> {
+    fn context_missing(self) -> Result<T> {
+        self.ok_or_else(|| anyhow::anyhow!("RPC frame truncated"))
+    }
+}
+
+/// One request frame read off the up-channel: a method tag plus its arguments.
+pub struct Request {
+    pub method: u8,
+    pub args: Vec<Value>,
+}
+
+impl Request {
+    fn parse(frame: &[u8]) -> Result<Self> {
+        let mut cursor = frame;
+        let method = take_u8(&mut cursor)?;
+        let argc = take_u8(&mut cursor)?;
+        let mut args = Vec::with_capacity(argc as usize);
+        for _ in 0..argc {
+            args.push(Value::read(&mut cursor)?);
+        }
+        Ok(Request { method, args })
+    }
+}
+
+type Handler = Box<dyn Fn(&[Value]) -> Result<Value> + Send>;
+
+/// Dispatches RPC requests the target sends on an up-channel to host-side handlers registered
+/// by method tag, writing each handler's return value back as a reply frame on the paired
+/// down-channel. This lets firmware call back into host-side services (file access, time,
+/// structured printing) instead of only emitting one-way log output.
+pub struct RpcServer {
+    handlers: HashMap<u8, Handler>,
+}
+
+impl Default for RpcServer {
+    fn default() -> Self {
+        RpcServer { handlers: HashMap::new() }
+    }
+}
+
+impl RpcServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler invoked for requests tagged `method`.
+    pub fn register(&mut self, method: u8, handler: impl Fn(&[Value]) -> Result<Value> + Send + 'static) {
+        self.handlers.insert(method, Box::new(handler));
+    }
+
+    /// Parses one request frame, dispatches it and writes the reply on `down`.
+    ///
+    /// Frame layout: `method: u8`, `argc: u8`, then `argc` type-tagged [`Value`]s (bytes and
+    /// strings carry a `u32` length prefix after the tag). The reply is a single type-tagged `Value` with no further framing, since the
+    /// RTT channel itself delimits one call from the next.
+    pub fn dispatch(&self, frame: &[u8], down: &DownChannel) -> Result<()> {
+        let request = Request::parse(frame)?;
+
+        let reply = match self.handlers.get(&request.method) {
+            Some(handler) => handler(&request.args)?,
+            None => bail!("no RPC handler registered for method {}", request.method),
+        };
+
+        let mut out = Vec::new();
+        reply.write(&mut out);
+        down.write(&out)?;
+
+        Ok(())
+    }
+}
+
+/// Reads complete RPC request frames off `up` as they arrive and dispatches them through
+/// `server`, writing replies to `down`. Frames carry no delimiter of their own: the firmware
+/// writes one frame, waits for the reply, then writes the next, so a single `read()` call's
+/// worth of bytes is taken as exactly one frame.
+pub fn pump(up: &UpChannel, down: &DownChannel, server: &RpcServer, buf: &mut [u8]) -> Result<()> {
+    let count = up.read(buf)?;
+    if count == 0 {
+        return Ok(());
+    }
+
+    server.dispatch(&buf[..count], down)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(value: Value) {
+        let mut bytes = Vec::new();
+        value.write(&mut bytes);
+        let mut cursor = bytes.as_slice();
+        assert_eq!(Value::read(&mut cursor).unwrap(), value);
+        assert!(cursor.is_empty());
+    }
+
+    #[test]
+    fn round_trips_every_value_variant() {
+        roundtrip(Value::U32(0xdead_beef));
+        roundtrip(Value::I32(-1));
+        roundtrip(Value::Bytes(vec![1, 2, 3]));
+        roundtrip(Value::Bytes(vec![]));
+        roundtrip(Value::Str("hello".to_owned()));
+        roundtrip(Value::Str(String::new()));
+    }
+
+    #[test]
+    fn rejects_unknown_tag() {
+        let mut cursor: &[u8] = &[0xff];
+        assert!(Value::read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_frame() {
+        let mut cursor: &[u8] = &[Value::TAG_U32, 1, 2];
+        assert!(Value::read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_length_prefixed_value() {
+        // Claims a 10-byte string but only 2 bytes follow.
+        let mut cursor: &[u8] = &[Value::TAG_STR, 10, 0, 0, 0, b'h', b'i'];
+        assert!(Value::read(&mut cursor).is_err());
+    }
+}